@@ -11,67 +11,210 @@ extern crate serde;
 extern crate serde_json;
 extern crate url;
 extern crate regex;
+extern crate net2;
+extern crate tokio_core;
+extern crate flate2;
+extern crate zstd;
 
 use clap::{Arg, App, ArgMatches};
-use futures::future::FutureResult;
+use futures::{Future, Stream, Async, Poll};
+use futures::stream;
 use std::process;
+use std::thread;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use net2::TcpBuilder;
+use net2::unix::UnixTcpBuilderExt;
+use tokio_core::reactor::Core;
+use tokio_core::net::TcpListener;
 use mongodb::{Client, ThreadedClient};
 use mongodb::db::{ThreadedDatabase, DatabaseInner};
-use mongodb::coll::options::FindOptions;
+use mongodb::coll::options::{FindOptions, AggregateOptions};
+use mongodb::common::{ReadPreference, ReadPreferenceType};
 use serde_json::Value;
 use regex::Regex;
-use hyper::{Get, StatusCode};
-use hyper::header::ContentLength;
+use hyper::{Get, Post, Put, Patch, Delete, StatusCode};
+use hyper::header::{ContentLength, ContentEncoding, Encoding};
 use hyper::server::{Http, Service, Request, Response};
+use hyper::Body;
 use std::collections::HashMap;
 use std::error::Error;
+use std::io::Write;
 
-struct QueryService<'a> {
-    db: &'a Arc<DatabaseInner>
+type ResponseFuture = Box<Future<Item = Response, Error = hyper::Error>>;
+
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy)]
+enum Compression {
+    Gzip,
+    Zstd
+}
+
+struct QueryService {
+    db: Arc<DatabaseInner>,
+    compression: Option<Compression>,
+    read_preference: Option<ReadPreference>
 }
 
-impl<'a> Service for QueryService<'a> {
+impl Service for QueryService {
     type Request = Request;
     type Response = Response;
     type Error = hyper::Error;
-    type Future = FutureResult<Response, hyper::Error>;
+    type Future = ResponseFuture;
 
     fn call(&self, req: Request) -> Self::Future {
-        if req.method() != &Get {
-            return get_failure_response(StatusCode::NotFound);
-        }
-        let collection_regex = Regex::new(r"^/([[:alpha:]_-]*)/?$").unwrap();
-        let collection = match collection_regex.captures(req.path()) {
-            Some(collection_match) => collection_match.get(1).map_or("", |m| m.as_str()),
+        let collection_regex = Regex::new(r"^/([[:alpha:]_-]*)(?:/(aggregate))?/?$").unwrap();
+        let captures = match collection_regex.captures(req.path()) {
+            Some(captures) => captures,
             None => return get_failure_response(StatusCode::BadRequest)
         };
+        let collection_name = captures.get(1).map_or("", |m| m.as_str()).to_string();
+        let aggregate = captures.get(2).is_some();
         let params = get_query_params(&req);
-        let collection = self.db.collection(collection);
-        let mut opts = FindOptions::new();
-        opts.limit = get_number_or(params.get("limit"), Some(20));
-        opts.skip = get_number_or(params.get("skip"), None);
-        match to_bson_document(params.get("sort")) {
-            Ok(v) => opts.sort = v,
-            Err(_) => return get_failure_response(StatusCode::BadRequest)
-        }
-        let query = match to_bson_document(params.get("query")) {
-            Ok(v) => v,
-            Err(_) => return get_failure_response(StatusCode::BadRequest)
-        };
-        match collection.find(query, Some(opts)) {
-            Ok(result) => {
-                let documents: Vec<String> = result
-                    .map(|item| bson::Bson::Document(item.unwrap()).to_string())
-                    .collect();
-                let output = format!("[{}]", documents.join(","));
-                return futures::future::ok(
-                    Response::new()
-                    .with_header(ContentLength(output.len() as u64))
-                    .with_body(output)
-                )
+        let collection = self.db.collection(&collection_name);
+        match *req.method() {
+            Get if aggregate || params.contains_key("pipeline") => {
+                let pipeline = match to_bson_documents(params.get("pipeline")) {
+                    Ok(v) => v,
+                    Err(_) => return get_failure_response(StatusCode::BadRequest)
+                };
+                let mut opts = AggregateOptions::new();
+                opts.read_preference = self.read_preference.clone();
+                match collection.aggregate(pipeline, Some(opts)) {
+                    Ok(result) => match negotiate_compression(&req, self.compression) {
+                        Some(codec) => get_compressed_response(result, codec),
+                        None => get_streamed_response(result),
+                    },
+                    Err(_) => get_failure_response(StatusCode::InternalServerError),
+                }
+            },
+            Get => {
+                let mut opts = FindOptions::new();
+                opts.limit = get_number_or(params.get("limit"), Some(20));
+                opts.skip = get_number_or(params.get("skip"), None);
+                opts.read_preference = self.read_preference.clone();
+                match to_bson_document(params.get("sort")) {
+                    Ok(v) => opts.sort = v,
+                    Err(_) => return get_failure_response(StatusCode::BadRequest)
+                }
+                match to_bson_document(params.get("fields").or_else(|| params.get("projection"))) {
+                    Ok(v) => opts.projection = v,
+                    Err(_) => return get_failure_response(StatusCode::BadRequest)
+                }
+                let query = match to_bson_document(params.get("query")) {
+                    Ok(v) => v,
+                    Err(_) => return get_failure_response(StatusCode::BadRequest)
+                };
+                match collection.find(query, Some(opts)) {
+                    Ok(result) => match negotiate_compression(&req, self.compression) {
+                        Some(codec) => get_compressed_response(result, codec),
+                        None => get_streamed_response(result),
+                    },
+                    Err(_) => get_failure_response(StatusCode::InternalServerError),
+                }
+            },
+            Post => {
+                // Read the body asynchronously: blocking on it here would deadlock the worker.
+                Box::new(read_body(req.body()).and_then(move |bytes| {
+                    let bytes = match bytes {
+                        Ok(bytes) => bytes,
+                        Err(_) => return get_failure_response(StatusCode::PayloadTooLarge)
+                    };
+                    let json: Value = match serde_json::from_slice(&bytes) {
+                        Ok(v) => v,
+                        Err(_) => return get_failure_response(StatusCode::BadRequest)
+                    };
+                    match json {
+                        Value::Array(items) => {
+                            let mut documents = Vec::with_capacity(items.len());
+                            for item in items {
+                                match to_bson_value(&item) {
+                                    Some(document) => documents.push(document),
+                                    None => return get_failure_response(StatusCode::BadRequest)
+                                }
+                            }
+                            if documents.is_empty() {
+                                let mut response = bson::Document::new();
+                                response.insert("inserted_ids", bson::Bson::Array(Vec::new()));
+                                return get_json_response(bson::Bson::Document(response).to_string());
+                            }
+                            match collection.insert_many(documents, None) {
+                                Ok(result) => {
+                                    let ids: Vec<bson::Bson> = result.inserted_ids
+                                        .unwrap_or_default()
+                                        .into_iter()
+                                        .map(|(_, id)| id)
+                                        .collect();
+                                    let mut response = bson::Document::new();
+                                    response.insert("inserted_ids", bson::Bson::Array(ids));
+                                    get_json_response(bson::Bson::Document(response).to_string())
+                                },
+                                Err(_) => get_failure_response(StatusCode::InternalServerError)
+                            }
+                        },
+                        _ => {
+                            let document = match to_bson_value(&json) {
+                                Some(document) => document,
+                                None => return get_failure_response(StatusCode::BadRequest)
+                            };
+                            match collection.insert_one(document, None) {
+                                Ok(result) => {
+                                    let mut response = bson::Document::new();
+                                    response.insert("inserted_id",
+                                                    result.inserted_id.unwrap_or(bson::Bson::Null));
+                                    get_json_response(bson::Bson::Document(response).to_string())
+                                },
+                                Err(_) => get_failure_response(StatusCode::InternalServerError)
+                            }
+                        }
+                    }
+                }))
             },
-            Err(_) => return get_failure_response(StatusCode::InternalServerError),
+            Put | Patch => {
+                let filter = match to_bson_document(params.get("query")) {
+                    Ok(v) => v.unwrap_or_else(bson::Document::new),
+                    Err(_) => return get_failure_response(StatusCode::BadRequest)
+                };
+                Box::new(read_body(req.body()).and_then(move |bytes| {
+                    let bytes = match bytes {
+                        Ok(bytes) => bytes,
+                        Err(_) => return get_failure_response(StatusCode::PayloadTooLarge)
+                    };
+                    let update: Value = match serde_json::from_slice(&bytes) {
+                        Ok(v) => v,
+                        Err(_) => return get_failure_response(StatusCode::BadRequest)
+                    };
+                    let update = match to_bson_value(&update) {
+                        Some(document) => document,
+                        None => return get_failure_response(StatusCode::BadRequest)
+                    };
+                    match collection.update_many(filter, update, None) {
+                        Ok(result) => {
+                            let mut response = bson::Document::new();
+                            response.insert("matched_count", result.matched_count);
+                            response.insert("modified_count", result.modified_count);
+                            get_json_response(bson::Bson::Document(response).to_string())
+                        },
+                        Err(_) => get_failure_response(StatusCode::InternalServerError)
+                    }
+                }))
+            },
+            Delete => {
+                let filter = match to_bson_document(params.get("query")) {
+                    Ok(v) => v.unwrap_or_else(bson::Document::new),
+                    Err(_) => return get_failure_response(StatusCode::BadRequest)
+                };
+                match collection.delete_many(filter, None) {
+                    Ok(result) => {
+                        let mut response = bson::Document::new();
+                        response.insert("deleted_count", result.deleted_count);
+                        get_json_response(bson::Bson::Document(response).to_string())
+                    },
+                    Err(_) => get_failure_response(StatusCode::InternalServerError)
+                }
+            },
+            _ => get_failure_response(StatusCode::NotFound)
         }
     }
 }
@@ -83,8 +226,172 @@ fn get_query_params(request: &Request) -> HashMap<String, String> {
     }
 }
 
-fn get_failure_response(code: StatusCode) -> FutureResult<Response, hyper::Error> {
-    futures::future::ok(Response::new().with_status(code))
+fn get_failure_response(code: StatusCode) -> ResponseFuture {
+    Box::new(futures::future::ok(Response::new().with_status(code)))
+}
+
+fn read_body(body: Body) -> Box<Future<Item = Result<Vec<u8>, ()>, Error = hyper::Error>> {
+    Box::new(body.fold(Ok(Vec::new()), |acc, chunk| {
+        let next = match acc {
+            Ok(mut buffer) => {
+                if buffer.len() + chunk.len() > MAX_BODY_SIZE {
+                    Err(())
+                } else {
+                    buffer.extend_from_slice(&chunk);
+                    Ok(buffer)
+                }
+            },
+            Err(_) => Err(())
+        };
+        futures::future::ok::<_, hyper::Error>(next)
+    }))
+}
+
+fn get_json_response(output: String) -> ResponseFuture {
+    Box::new(futures::future::ok(
+        Response::new()
+        .with_header(ContentLength(output.len() as u64))
+        .with_body(output)
+    ))
+}
+
+fn document_chunk_stream<I>(cursor: I) -> Box<Stream<Item = hyper::Chunk, Error = hyper::Error> + Send>
+    where I: Iterator<Item = mongodb::Result<bson::ordered::OrderedDocument>> + Send + 'static
+{
+    // Cursor batches are fetched synchronously on the reactor (blocking driver).
+    let documents = cursor.enumerate().map(|(index, item)| {
+        item
+            .map(|document| {
+                let document = bson::Bson::Document(document).to_string();
+                let piece = if index == 0 { document } else { format!(",{}", document) };
+                hyper::Chunk::from(piece)
+            })
+            .map_err(|error| hyper::Error::Io(
+                std::io::Error::new(std::io::ErrorKind::Other, format!("{}", error))))
+    });
+    Box::new(
+        stream::once::<_, hyper::Error>(Ok(hyper::Chunk::from("[")))
+            .chain(stream::iter_result(documents))
+            .chain(stream::once(Ok(hyper::Chunk::from("]")))))
+}
+
+fn get_streamed_response<I>(cursor: I) -> ResponseFuture
+    where I: Iterator<Item = mongodb::Result<bson::ordered::OrderedDocument>> + Send + 'static
+{
+    Box::new(futures::future::ok(Response::new().with_body(document_chunk_stream(cursor))))
+}
+
+fn negotiate_compression(request: &Request, configured: Option<Compression>) -> Option<Compression> {
+    configured.and_then(|codec| {
+        let name = match codec {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd"
+        };
+        match request.headers().get::<hyper::header::AcceptEncoding>() {
+            Some(accepted) if format!("{}", accepted).contains(name) => Some(codec),
+            _ => None
+        }
+    })
+}
+
+enum StreamEncoder {
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<Vec<u8>>)
+}
+
+impl StreamEncoder {
+    fn new(codec: Compression) -> std::io::Result<StreamEncoder> {
+        match codec {
+            Compression::Gzip => Ok(StreamEncoder::Gzip(
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()))),
+            Compression::Zstd => Ok(StreamEncoder::Zstd(
+                zstd::stream::write::Encoder::new(Vec::new(), 0)?))
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match *self {
+            StreamEncoder::Gzip(ref mut encoder) => {
+                encoder.write_all(data)?;
+                Ok(std::mem::replace(encoder.get_mut(), Vec::new()))
+            },
+            StreamEncoder::Zstd(ref mut encoder) => {
+                encoder.write_all(data)?;
+                Ok(std::mem::replace(encoder.get_mut(), Vec::new()))
+            }
+        }
+    }
+
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            StreamEncoder::Gzip(encoder) => encoder.finish(),
+            StreamEncoder::Zstd(encoder) => encoder.finish()
+        }
+    }
+}
+
+// Compresses a chunk stream incrementally so compression composes with the streaming
+// body: the encoder only retains its own window, never the whole result set. Nothing
+// is flushed per chunk: a poll emits only the bytes the encoder has produced so far.
+struct CompressedBody {
+    source: Box<Stream<Item = hyper::Chunk, Error = hyper::Error> + Send>,
+    encoder: Option<StreamEncoder>
+}
+
+impl Stream for CompressedBody {
+    type Item = hyper::Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<hyper::Chunk>, hyper::Error> {
+        loop {
+            if self.encoder.is_none() {
+                return Ok(Async::Ready(None));
+            }
+            match self.source.poll()? {
+                Async::Ready(Some(chunk)) => {
+                    let compressed = {
+                        let encoder = self.encoder.as_mut().unwrap();
+                        encoder.write(&chunk).map_err(hyper::Error::Io)?
+                    };
+                    if compressed.is_empty() {
+                        continue;
+                    }
+                    return Ok(Async::Ready(Some(hyper::Chunk::from(compressed))));
+                },
+                Async::Ready(None) => {
+                    let trailer = self.encoder.take().unwrap().finish().map_err(hyper::Error::Io)?;
+                    return Ok(Async::Ready(Some(hyper::Chunk::from(trailer))));
+                },
+                Async::NotReady => return Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+fn get_compressed_response<I>(cursor: I, codec: Compression) -> ResponseFuture
+    where I: Iterator<Item = mongodb::Result<bson::ordered::OrderedDocument>> + Send + 'static
+{
+    let encoder = match StreamEncoder::new(codec) {
+        Ok(encoder) => encoder,
+        Err(_) => return get_failure_response(StatusCode::InternalServerError)
+    };
+    let encoding = match codec {
+        Compression::Gzip => Encoding::Gzip,
+        Compression::Zstd => Encoding::EncodingExt("zstd".to_owned())
+    };
+    let body = CompressedBody { source: document_chunk_stream(cursor), encoder: Some(encoder) };
+    let body: Box<Stream<Item = hyper::Chunk, Error = hyper::Error> + Send> = Box::new(body);
+    Box::new(futures::future::ok(
+        Response::new()
+        .with_header(ContentEncoding(vec![encoding]))
+        .with_body(body)
+    ))
+}
+
+fn to_bson_value(value: &Value) -> Option<bson::ordered::OrderedDocument> {
+    bson::to_bson(value)
+        .ok()
+        .and_then(|bson_value| bson_value.as_document().cloned())
 }
 
 fn to_bson_document(query_option: Option<&String>) -> Result<Option<bson::ordered::OrderedDocument>, Box<Error>> {
@@ -98,6 +405,26 @@ fn to_bson_document(query_option: Option<&String>) -> Result<Option<bson::ordere
     Ok(None)
 }
 
+fn to_bson_documents(query_option: Option<&String>) -> Result<Vec<bson::ordered::OrderedDocument>, Box<Error>> {
+    let mut documents = Vec::new();
+    if let Some(query_string) = query_option {
+        let json: Value = serde_json::from_str(query_string)?;
+        let bson_value = bson::to_bson(&json)?;
+        match bson_value.as_array() {
+            Some(bson_array) => {
+                for stage in bson_array {
+                    match stage.as_document() {
+                        Some(bson_document) => documents.push((*bson_document).clone()),
+                        None => return Err(From::from("aggregation stage is not a document"))
+                    }
+                }
+            },
+            None => return Err(From::from("pipeline must be a JSON array"))
+        }
+    }
+    Ok(documents)
+}
+
 fn get_number_or(query_option: Option<&String>, default: Option<i64>) -> Option<i64> {
     if let Some(limit_string) = query_option {
         if let Ok(limit) = limit_string.parse::<i64>() {
@@ -132,6 +459,22 @@ fn get_configuration() -> ArgMatches<'static> {
              .long("password")
              .requires("username")
              .help("Password"))
+        .arg(Arg::with_name("threads")
+             .takes_value(true)
+             .short("t")
+             .long("threads")
+             .help("Number of worker threads"))
+        .arg(Arg::with_name("compression")
+             .takes_value(true)
+             .long("compression")
+             .possible_values(&["gzip", "zstd"])
+             .help("Compress result payloads when the client accepts the encoding"))
+        .arg(Arg::with_name("read-preference")
+             .takes_value(true)
+             .long("read-preference")
+             .possible_values(&["primary", "primaryPreferred", "secondary",
+                                "secondaryPreferred", "nearest"])
+             .help("Read preference for replica-set / sharded deployments"))
         .arg(Arg::with_name("v")
              .short("v")
              .multiple(true)
@@ -175,20 +518,81 @@ fn create_database_connection(config: &ArgMatches) -> Arc<DatabaseInner> {
     return db;
 }
 
-fn run(config: &ArgMatches) {
-    let db = create_database_connection(&config);
-    let host = config.value_of("host").unwrap_or("127.0.0.1");
-    let port = config.value_of("port").unwrap_or("80");
-    let address = format!("{}:{}", host, port).parse().unwrap();
-    let server = match Http::new().bind(&address, move || Ok(QueryService {db: &db})) {
+fn bind_reuse_listener(address: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    TcpBuilder::new_v4()?
+        .reuse_address(true)?
+        .reuse_port(true)?
+        .bind(address)?
+        .listen(1024)
+}
+
+fn serve(address: SocketAddr, db: Arc<DatabaseInner>, compression: Option<Compression>,
+         read_preference: Option<ReadPreference>) {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let listener = match bind_reuse_listener(address) {
         Err(_) => {
             println!("Unable to bind to {}", address);
             process::exit(0x0100)
         },
         Ok(val) => val
     };
-    println!("Listening on http://{} with 1 thread.", server.local_addr().unwrap());
-    server.run().unwrap()
+    let listener = match TcpListener::from_listener(listener, &address, &handle) {
+        Err(_) => {
+            println!("Unable to bind to {}", address);
+            process::exit(0x0100)
+        },
+        Ok(val) => val
+    };
+    let http = Http::new();
+    let server = listener.incoming().for_each(move |(socket, remote)| {
+        http.bind_connection(&handle, socket, remote,
+                             QueryService { db: db.clone(), compression,
+                                            read_preference: read_preference.clone() });
+        Ok(())
+    });
+    core.run(server).unwrap();
+}
+
+fn get_compression(config: &ArgMatches) -> Option<Compression> {
+    match config.value_of("compression") {
+        Some("gzip") => Some(Compression::Gzip),
+        Some("zstd") => Some(Compression::Zstd),
+        _ => None
+    }
+}
+
+fn get_read_preference(config: &ArgMatches) -> Option<ReadPreference> {
+    let mode = match config.value_of("read-preference") {
+        Some("primary") => ReadPreferenceType::Primary,
+        Some("primaryPreferred") => ReadPreferenceType::PrimaryPreferred,
+        Some("secondary") => ReadPreferenceType::Secondary,
+        Some("secondaryPreferred") => ReadPreferenceType::SecondaryPreferred,
+        Some("nearest") => ReadPreferenceType::Nearest,
+        _ => return None
+    };
+    Some(ReadPreference::new(mode, None))
+}
+
+fn run(config: &ArgMatches) {
+    let db = create_database_connection(&config);
+    let host = config.value_of("host").unwrap_or("127.0.0.1");
+    let port = config.value_of("port").unwrap_or("80");
+    let address: SocketAddr = format!("{}:{}", host, port).parse().unwrap();
+    let threads = get_number_or(config.value_of("threads").map(String::from).as_ref(), Some(4))
+        .unwrap_or(4) as usize;
+    let compression = get_compression(&config);
+    let read_preference = get_read_preference(&config);
+    let mut workers = Vec::with_capacity(threads);
+    for _ in 0..threads {
+        let db = db.clone();
+        let read_preference = read_preference.clone();
+        workers.push(thread::spawn(move || serve(address, db, compression, read_preference)));
+    }
+    println!("Listening on http://{} with {} threads.", address, threads);
+    for worker in workers {
+        worker.join().unwrap();
+    }
 }
 
 fn main() {